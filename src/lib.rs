@@ -0,0 +1,824 @@
+use data_encoding::HEXLOWER;
+use notify::{RecursiveMode, Watcher};
+use ring::hmac::Tag;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+    sync::Arc,
+    time::Duration,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use swc_common::{self, SourceMap};
+use swc_ecma_visit::{noop_visit_type, Visit, VisitWith};
+use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax, TsConfig};
+use threadpool::ThreadPool;
+use walkdir::WalkDir;
+
+const GRAPHQL_SUFFIX: &str = ".graphql.ts";
+const CONCRETE_REQUEST: &str = "ConcreteRequest";
+pub const CACHE_FILE: &str = ".sign-queries-cache.json";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Strategy {
+    Manual,
+    Swc,
+}
+
+impl From<String> for Strategy {
+    fn from(s: String) -> Self {
+        match &*s {
+            "swc" | "SWC" | "Swc" => Self::Swc,
+            _ => Self::Manual,
+        }
+    }
+}
+
+impl Strategy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Manual => "manual",
+            Self::Swc => "swc",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CollisionPolicy {
+    Error,
+    Suffix,
+    First,
+}
+
+impl From<String> for CollisionPolicy {
+    fn from(s: String) -> Self {
+        match &*s {
+            "error" => Self::Error,
+            "first" => Self::First,
+            _ => Self::Suffix,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    MissingDirectory,
+    MissingSigningKey,
+    IoError(std::io::Error),
+    ParamSerialization,
+    SignatureFileCreation,
+    SignatureSerialization,
+    DuplicateOperation(String, Vec<PathBuf>),
+    SignatureFileRead,
+    SignatureDeserialization,
+    WatcherInit,
+    Load { path: PathBuf, message: String },
+    Parse { path: PathBuf, message: String },
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingDirectory => write!(f, "no directory given"),
+            Self::MissingSigningKey => write!(f, "no signing key given"),
+            Self::IoError(e) => write!(f, "io error: {}", e),
+            Self::ParamSerialization => write!(f, "failed to deserialize params"),
+            Self::SignatureFileCreation => write!(f, "failed to create signatures file"),
+            Self::SignatureSerialization => write!(f, "failed to serialize signatures"),
+            Self::DuplicateOperation(name, paths) => {
+                write!(f, "duplicate operation {} in {:?}", name, paths)
+            }
+            Self::SignatureFileRead => write!(f, "failed to read signatures file"),
+            Self::SignatureDeserialization => write!(f, "failed to deserialize signatures file"),
+            Self::WatcherInit => write!(f, "failed to start filesystem watcher"),
+            Self::Load { path, message } => write!(f, "{}: failed to load file: {}", path.display(), message),
+            Self::Parse { path, message } => write!(f, "{}: failed to parse: {}", path.display(), message),
+        }
+    }
+}
+
+/// Configuration shared by every file signed during a run.
+pub struct SignerConfig {
+    pub strategy: Strategy,
+    pub key: ring::hmac::Key,
+    pub on_collision: CollisionPolicy,
+}
+
+impl SignerConfig {
+    pub fn new(key: ring::hmac::Key) -> Self {
+        Self {
+            strategy: Strategy::Swc,
+            key,
+            on_collision: CollisionPolicy::Suffix,
+        }
+    }
+}
+
+/// The HMAC signature for a single `ConcreteRequest` operation.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub name: String,
+    pub hash: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+struct Params {
+    #[serde(rename = "cacheID")]
+    cache_id: String,
+    id: Option<String>,
+    metadata: Value,
+    name: String,
+    #[serde(rename = "operationKind")]
+    operation_kind: String,
+    text: String,
+}
+
+fn compute_digest<P: AsRef<Path>>(filepath: P, key: ring::hmac::Key, strategy: Strategy) -> Result<Option<(Tag, String, PathBuf)>, Error> {
+    let path = filepath.as_ref().to_owned();
+    let params = match strategy {
+        Strategy::Swc => get_params_swc(&path),
+        Strategy::Manual => get_params(&path),
+    };
+    let digest = match params? {
+        Some(params) => {
+            let tag = ring::hmac::sign(&key, params.text.as_bytes());
+            Some((tag, params.name, path))
+        }
+        None => None
+    };
+
+    Ok(digest)
+}
+
+fn is_graphql<P: AsRef<Path>>(filepath: P) -> bool {
+    filepath
+        .as_ref()
+        .file_name()
+        .map(|s| s.to_string_lossy().ends_with(GRAPHQL_SUFFIX))
+        .unwrap_or(false)
+}
+
+fn get_params<P: AsRef<Path>>(filepath: P) -> Result<Option<Params>, Error> {
+    let contents = std::fs::read_to_string(filepath)?;
+
+    let params = match find_params(&contents) {
+        Some(raw_params) => {
+            let params: Params = serde_json::from_str(&raw_params).map_err(|_| Error::ParamSerialization)?;
+            Some(params)
+        }
+        None => None
+    };
+    Ok(params)
+}
+
+#[derive(Default)]
+struct MyCollector {
+    params: bool,
+    name: String,
+    text: String,
+    done: i32,
+}
+impl Visit for MyCollector {
+    noop_visit_type!();
+
+    fn visit_key_value_prop(&mut self, n: &swc_ecma_ast::KeyValueProp) {
+        if self.done == 2 {
+            return
+        }
+        if let swc_ecma_ast::PropName::Str(s) = &n.key {
+            if self.params {
+                if s.raw.as_deref() == Some("\"name\"") {
+                    if let swc_ecma_ast::Expr::Lit(swc_ecma_ast::Lit::Str(ss)) = &*n.value {
+                        //self.name = ss.value.as_deref().unwrap().trim_matches('"').to_owned();
+                        self.name = ss.value.to_string();
+                        self.done += 1;
+                    }
+                }
+                if s.raw.as_deref() == Some("\"text\"") {
+                    if let swc_ecma_ast::Expr::Lit(swc_ecma_ast::Lit::Str(ss)) = &*n.value {
+                        self.text = ss.value.to_string();
+                        self.done += 1;
+                    }
+                }
+            } else if s.raw.as_deref() == Some("\"params\"") {
+                self.params = true;
+            }
+        }
+        n.visit_children_with(self);
+    }
+}
+
+fn get_params_swc<P: AsRef<Path>>(filepath: P) -> Result<Option<Params>, Error> {
+    let path = filepath.as_ref().to_owned();
+    let cm: Arc<SourceMap> = Default::default();
+    let fm = cm.load_file(&path).map_err(|e| Error::Load {
+        path: path.clone(),
+        message: e.to_string(),
+    })?;
+    let lexer = Lexer::new(
+        Syntax::Typescript(TsConfig {
+            no_early_errors: true,
+            tsx: false,
+            ..Default::default()
+        }),
+        Default::default(),
+        StringInput::from(&*fm),
+        None,
+    );
+
+    let mut parser = Parser::new_from(lexer);
+    let module = parser.parse_typescript_module().map_err(|e| Error::Parse {
+        path: path.clone(),
+        message: format!("{:?}", e),
+    })?;
+    let mut visitor = MyCollector::default();
+    module.visit_with(&mut visitor);
+    if !visitor.params || visitor.done < 2 {
+        return Ok(None);
+    }
+    Ok(Some(Params {
+        name: visitor.name,
+        text: visitor.text,
+        ..Default::default()
+    }
+    ))
+}
+
+fn find_params(contents: &str) -> Option<String> {
+    let concrete = contents.find(CONCRETE_REQUEST)?;
+    let rest = &contents[concrete..];
+    let mut found_params = None;
+    let mut params = Vec::new();
+    for line in rest.lines() {
+        if let Some(end) = found_params.as_ref() {
+            params.push(line);
+            if line.starts_with(end) {
+                break;
+            }
+        }
+        if line.trim().starts_with("\"params\": {") {
+            let mut ws_count = 0;
+            for c in line.chars() {
+                if c.is_whitespace() {
+                    ws_count += 1;
+                } else {
+                    break;
+                }
+            }
+            // This makes a string that has the same leading whitespace as "params": {
+            // but with a single } which is what we will be looking for as the closing brace
+            // for the params object.
+            found_params = Some(format!("{0: >1$}", '}', ws_count+1));
+            // We insert a single opening brace which strips the "params": part
+            params.push("{");
+            continue;
+        }
+    }
+    Some(params.join(""))
+}
+
+/// Signs a single `.graphql.ts` file, returning `None` if it does not contain
+/// a `ConcreteRequest`.
+pub fn sign_file<P: AsRef<Path>>(filepath: P, config: &SignerConfig) -> Result<Option<Signature>, Error> {
+    let digest = compute_digest(filepath, config.key.clone(), config.strategy)?;
+    Ok(digest.map(|(tag, name, _path)| Signature {
+        name,
+        hash: HEXLOWER.encode(tag.as_ref()),
+    }))
+}
+
+/// The result of signing a directory: the signatures that were produced,
+/// plus any per-file errors that did not stop the rest of the tree from
+/// being signed.
+#[derive(Debug, Default)]
+pub struct SignOutcome {
+    pub signatures: BTreeMap<String, String>,
+    pub failures: Vec<Error>,
+}
+
+/// Walks `dir` for `.graphql.ts` files, signs each one in a thread pool, and
+/// resolves any operation-name collisions per `config.on_collision`.
+pub fn sign_directory<P: AsRef<Path>>(dir: P, config: &SignerConfig) -> Result<SignOutcome, Error> {
+    let pool = ThreadPool::new(num_cpus::get());
+    let (tx, rx) = channel();
+
+    for entry in WalkDir::new(dir)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.path().is_dir() && is_graphql(e.path())) {
+            let path = entry.path().to_owned();
+            let tx = tx.clone();
+            let key = config.key.clone();
+            let strategy = config.strategy;
+            pool.execute(move || {
+                let digest = compute_digest(path, key, strategy);
+                tx.send(digest).expect("Could not send data!");
+            });
+        }
+
+    drop(tx);
+
+    let mut entries: Vec<(String, String, PathBuf)> = Vec::new();
+    let mut failures = Vec::new();
+    for t in rx.iter() {
+        match t {
+            Ok(Some((sha, name, path))) => {
+                let hash = HEXLOWER.encode(sha.as_ref());
+                entries.push((name, hash, path));
+            }
+            Ok(None) => {}
+            Err(e) => failures.push(e),
+        }
+    }
+
+    let signatures = resolve_collisions(entries, config.on_collision)?;
+    Ok(SignOutcome { signatures, failures })
+}
+
+/// Groups `(name, hash, path)` triples by name and applies `policy` to any
+/// name that appears more than once, in sorted-path order.
+fn resolve_collisions(mut entries: Vec<(String, String, PathBuf)>, policy: CollisionPolicy) -> Result<BTreeMap<String, String>, Error> {
+    entries.sort_by(|a, b| a.2.cmp(&b.2));
+
+    let mut grouped: BTreeMap<String, Vec<(String, PathBuf)>> = BTreeMap::new();
+    for (name, hash, path) in entries {
+        grouped.entry(name).or_default().push((hash, path));
+    }
+
+    let mut signatures = BTreeMap::new();
+    for (name, mut variants) in grouped {
+        if variants.len() == 1 {
+            let (hash, _) = variants.remove(0);
+            signatures.insert(name, hash);
+            continue;
+        }
+
+        match policy {
+            CollisionPolicy::Error => {
+                let paths = variants.into_iter().map(|(_, path)| path).collect();
+                return Err(Error::DuplicateOperation(name, paths));
+            }
+            CollisionPolicy::First => {
+                let (hash, _) = variants.remove(0);
+                signatures.insert(name, hash);
+            }
+            CollisionPolicy::Suffix => {
+                for (i, (hash, _)) in variants.into_iter().enumerate() {
+                    let key = if i == 0 { name.clone() } else { format!("{}{}", name, i) };
+                    signatures.insert(key, hash);
+                }
+            }
+        }
+    }
+
+    Ok(signatures)
+}
+
+/// The differences found between a checked-in `signatures.json` and what the
+/// source tree would produce right now.
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// Operations whose recorded hash no longer matches the current source text.
+    pub stale: Vec<String>,
+    /// Operations present in the signatures file but no longer found in the tree.
+    pub missing_from_tree: Vec<String>,
+    /// Operations found in the tree but not recorded in the signatures file.
+    pub missing_from_file: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.stale.is_empty() && self.missing_from_tree.is_empty() && self.missing_from_file.is_empty()
+    }
+}
+
+/// Re-walks `dir`, recomputes every signature, and diffs the result against
+/// the signatures already recorded at `signatures_path`. Used as a CI guard
+/// that checked-in signatures stay in sync with the generated Relay artifacts.
+pub fn verify_directory<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, signatures_path: Q, config: &SignerConfig) -> Result<VerifyReport, Error> {
+    let f = File::open(signatures_path).map_err(|_| Error::SignatureFileRead)?;
+    let recorded: BTreeMap<String, String> =
+        serde_json::from_reader(f).map_err(|_| Error::SignatureDeserialization)?;
+
+    let current = sign_directory(dir, config)?.signatures;
+
+    Ok(diff_signatures(&recorded, &current))
+}
+
+/// Diffs `recorded` (what's checked in) against `current` (what the source
+/// tree would produce right now).
+fn diff_signatures(recorded: &BTreeMap<String, String>, current: &BTreeMap<String, String>) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    for (name, hash) in recorded {
+        match current.get(name) {
+            Some(current_hash) if current_hash == hash => {}
+            Some(_) => report.stale.push(name.clone()),
+            None => report.missing_from_tree.push(name.clone()),
+        }
+    }
+    for name in current.keys() {
+        if !recorded.contains_key(name) {
+            report.missing_from_file.push(name.clone());
+        }
+    }
+
+    report
+}
+
+/// Writes `signatures` to `output_file` using the repo's tab-indented JSON
+/// style, via a temp file + rename so readers never see a partial write.
+fn write_signatures_atomic<P: AsRef<Path>>(output_file: P, signatures: &BTreeMap<String, String>) -> Result<(), Error> {
+    let output_file = output_file.as_ref();
+    let tmp_file = output_file.with_extension("json.tmp");
+
+    let f = File::create(&tmp_file).map_err(|_| Error::SignatureFileCreation)?;
+    let writer = BufWriter::new(f);
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"\t");
+    let mut ser = serde_json::Serializer::with_formatter(writer, formatter);
+    signatures.serialize(&mut ser).map_err(|_| Error::SignatureSerialization)?;
+    ser.into_inner().flush()?;
+
+    std::fs::rename(&tmp_file, output_file)?;
+    Ok(())
+}
+
+/// Resolves the in-memory `path -> (name, hash)` watch state into the final
+/// `name -> hash` map, applying the same collision policy as a full sign.
+fn resolve_watch_state(state: &BTreeMap<PathBuf, (String, String)>, policy: CollisionPolicy) -> Result<BTreeMap<String, String>, Error> {
+    let entries = state
+        .iter()
+        .map(|(path, (name, hash))| (name.clone(), hash.clone(), path.clone()))
+        .collect();
+    resolve_collisions(entries, policy)
+}
+
+fn changed_graphql_paths(event: &notify::Event, out: &mut Vec<PathBuf>) {
+    for path in &event.paths {
+        if is_graphql(path) {
+            out.push(path.clone());
+        }
+    }
+}
+
+/// A progress notification emitted by `watch_directory` so a caller embedding
+/// the library can route it to its own logging instead of us writing to
+/// stdout/stderr directly.
+#[derive(Debug)]
+pub enum WatchProgress {
+    /// A re-sign batch completed; `tracked` is the number of operations
+    /// currently known.
+    Resigned { tracked: usize },
+    /// A single file failed to sign; the rest of the batch still proceeded.
+    Failed(Error),
+}
+
+/// Signs `dir` once, then keeps running, re-signing only the `.graphql.ts`
+/// files a filesystem watcher reports as changed and rewriting
+/// `output_file` after each quiescent batch. Runs until the watch channel
+/// closes.
+pub fn watch_directory<P: AsRef<Path>, Q: AsRef<Path>>(
+    dir: P,
+    output_file: Q,
+    config: &SignerConfig,
+    mut on_progress: impl FnMut(WatchProgress),
+) -> Result<(), Error> {
+    let dir = dir.as_ref().to_owned();
+    let output_file = output_file.as_ref().to_owned();
+
+    let mut state: BTreeMap<PathBuf, (String, String)> = BTreeMap::new();
+    for entry in WalkDir::new(&dir)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.path().is_dir() && is_graphql(e.path()))
+    {
+        let path = entry.path().to_owned();
+        match sign_file(&path, config) {
+            Ok(Some(sig)) => {
+                state.insert(path, (sig.name, sig.hash));
+            }
+            Ok(None) => {}
+            Err(e) => on_progress(WatchProgress::Failed(e)),
+        }
+    }
+    write_signatures_atomic(&output_file, &resolve_watch_state(&state, config.on_collision)?)?;
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            let _ = tx.send(event);
+        }
+    })
+    .map_err(|_| Error::WatcherInit)?;
+    watcher
+        .watch(&dir, RecursiveMode::Recursive)
+        .map_err(|_| Error::WatcherInit)?;
+
+    // Debounce bursts of events (e.g. a save that touches the file twice)
+    // into a single re-sign pass.
+    let debounce = Duration::from_millis(200);
+    while let Ok(first) = rx.recv() {
+        let mut changed = Vec::new();
+        changed_graphql_paths(&first, &mut changed);
+        while let Ok(event) = rx.recv_timeout(debounce) {
+            changed_graphql_paths(&event, &mut changed);
+        }
+        if changed.is_empty() {
+            continue;
+        }
+
+        for path in changed {
+            if path.exists() {
+                match sign_file(&path, config) {
+                    Ok(Some(sig)) => {
+                        state.insert(path, (sig.name, sig.hash));
+                    }
+                    Ok(None) => {
+                        state.remove(&path);
+                    }
+                    Err(e) => on_progress(WatchProgress::Failed(e)),
+                }
+            } else {
+                state.remove(&path);
+            }
+        }
+
+        write_signatures_atomic(&output_file, &resolve_watch_state(&state, config.on_collision)?)?;
+        on_progress(WatchProgress::Resigned { tracked: state.len() });
+    }
+
+    Ok(())
+}
+
+/// A file's modification time at full (seconds, nanoseconds) resolution.
+/// Whole-second truncation would let a rewrite that lands in the same
+/// wall-clock second as the cached entry be mistaken for an unchanged file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    stamp: FileStamp,
+    name: String,
+    hash: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cache {
+    key_fingerprint: String,
+    #[serde(default)]
+    strategy: String,
+    #[serde(default)]
+    entries: BTreeMap<PathBuf, CacheEntry>,
+}
+
+/// A fingerprint of the signing key, stored alongside the cache so it can be
+/// invalidated whenever the key changes.
+pub fn key_fingerprint(signing_key: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, signing_key.as_bytes());
+    HEXLOWER.encode(digest.as_ref())
+}
+
+fn load_cache<P: AsRef<Path>>(cache_path: P, key_fingerprint: &str, strategy: Strategy) -> Cache {
+    let cache: Cache = File::open(cache_path.as_ref())
+        .ok()
+        .and_then(|f| serde_json::from_reader(f).ok())
+        .unwrap_or_default();
+
+    if cache.key_fingerprint == key_fingerprint && cache.strategy == strategy.as_str() {
+        cache
+    } else {
+        Cache {
+            key_fingerprint: key_fingerprint.to_string(),
+            strategy: strategy.as_str().to_string(),
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+fn file_stamp<P: AsRef<Path>>(path: P) -> Option<FileStamp> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some(FileStamp {
+        modified_secs: since_epoch.as_secs(),
+        modified_nanos: since_epoch.subsec_nanos(),
+    })
+}
+
+/// The result of a cached signing run: the usual `SignOutcome` plus how many
+/// signatures were served from the cache versus freshly computed.
+#[derive(Debug, Default)]
+pub struct CachedSignOutcome {
+    pub outcome: SignOutcome,
+    pub reused: usize,
+    pub resigned: usize,
+}
+
+/// Like `sign_directory`, but consults `cache_path` first and only dispatches
+/// new or modified files to `get_params_swc`/`ring::hmac::sign`, reusing the
+/// previously computed signature for files whose modification time hasn't
+/// changed since the last run. The cache is invalidated whenever
+/// `signing_key_fingerprint` (see `key_fingerprint`) or `config.strategy`
+/// no longer matches what it was built with. Only files that produced a
+/// signature are cached: files with no operation, or that failed to sign,
+/// are parsed again on every run even when unchanged.
+pub fn sign_directory_cached<P: AsRef<Path>, Q: AsRef<Path>>(
+    dir: P,
+    config: &SignerConfig,
+    signing_key_fingerprint: &str,
+    cache_path: Q,
+) -> Result<CachedSignOutcome, Error> {
+    let cache_path = cache_path.as_ref().to_owned();
+    let cache = load_cache(&cache_path, signing_key_fingerprint, config.strategy);
+
+    let pool = ThreadPool::new(num_cpus::get());
+    let (tx, rx) = channel();
+    let mut reused = 0usize;
+
+    for entry in WalkDir::new(dir)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| !e.path().is_dir() && is_graphql(e.path()))
+    {
+        let path = entry.path().to_owned();
+        let stamp = file_stamp(&path);
+
+        let cached = stamp.and_then(|stamp| {
+            cache
+                .entries
+                .get(&path)
+                .filter(|cached| cached.stamp == stamp)
+                .cloned()
+        });
+
+        if let Some(cached) = cached {
+            reused += 1;
+            tx.send(Ok(Some((cached.name, cached.hash, path, Some(cached.stamp)))))
+                .expect("Could not send data!");
+            continue;
+        }
+
+        let tx = tx.clone();
+        let key = config.key.clone();
+        let strategy = config.strategy;
+        pool.execute(move || {
+            let digest = compute_digest(path, key, strategy).map(|opt| {
+                opt.map(|(tag, name, path)| {
+                    let hash = HEXLOWER.encode(tag.as_ref());
+                    let stamp = file_stamp(&path);
+                    (name, hash, path, stamp)
+                })
+            });
+            tx.send(digest).expect("Could not send data!");
+        });
+    }
+
+    drop(tx);
+
+    let mut entries: Vec<(String, String, PathBuf)> = Vec::new();
+    let mut failures = Vec::new();
+    let mut fresh_entries = BTreeMap::new();
+    for t in rx.iter() {
+        match t {
+            Ok(Some((name, hash, path, stamp))) => {
+                if let Some(stamp) = stamp {
+                    fresh_entries.insert(
+                        path.clone(),
+                        CacheEntry {
+                            stamp,
+                            name: name.clone(),
+                            hash: hash.clone(),
+                        },
+                    );
+                }
+                entries.push((name, hash, path));
+            }
+            Ok(None) => {}
+            Err(e) => failures.push(e),
+        }
+    }
+
+    let resigned = entries.len() - reused;
+
+    let fresh_cache = Cache {
+        key_fingerprint: signing_key_fingerprint.to_string(),
+        strategy: config.strategy.as_str().to_string(),
+        entries: fresh_entries,
+    };
+    if let Ok(f) = File::create(&cache_path) {
+        let _ = serde_json::to_writer(f, &fresh_cache);
+    }
+
+    let signatures = resolve_collisions(entries, config.on_collision)?;
+    Ok(CachedSignOutcome {
+        outcome: SignOutcome { signatures, failures },
+        reused,
+        resigned,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, hash: &str, path: &str) -> (String, String, PathBuf) {
+        (name.to_string(), hash.to_string(), PathBuf::from(path))
+    }
+
+    #[test]
+    fn resolve_collisions_passes_through_unique_names() {
+        let entries = vec![entry("OpA", "hash-a", "a.graphql.ts"), entry("OpB", "hash-b", "b.graphql.ts")];
+        let signatures = resolve_collisions(entries, CollisionPolicy::Suffix).unwrap();
+        assert_eq!(signatures.get("OpA"), Some(&"hash-a".to_string()));
+        assert_eq!(signatures.get("OpB"), Some(&"hash-b".to_string()));
+        assert_eq!(signatures.len(), 2);
+    }
+
+    #[test]
+    fn resolve_collisions_suffix_orders_by_path() {
+        let entries = vec![
+            entry("Op", "hash-z", "z.graphql.ts"),
+            entry("Op", "hash-a", "a.graphql.ts"),
+        ];
+        let signatures = resolve_collisions(entries, CollisionPolicy::Suffix).unwrap();
+        assert_eq!(signatures.get("Op"), Some(&"hash-a".to_string()));
+        assert_eq!(signatures.get("Op1"), Some(&"hash-z".to_string()));
+    }
+
+    #[test]
+    fn resolve_collisions_first_keeps_lowest_path() {
+        let entries = vec![
+            entry("Op", "hash-z", "z.graphql.ts"),
+            entry("Op", "hash-a", "a.graphql.ts"),
+        ];
+        let signatures = resolve_collisions(entries, CollisionPolicy::First).unwrap();
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures.get("Op"), Some(&"hash-a".to_string()));
+    }
+
+    #[test]
+    fn resolve_collisions_error_reports_duplicate() {
+        let entries = vec![
+            entry("Op", "hash-a", "a.graphql.ts"),
+            entry("Op", "hash-b", "b.graphql.ts"),
+        ];
+        match resolve_collisions(entries, CollisionPolicy::Error) {
+            Err(Error::DuplicateOperation(name, paths)) => {
+                assert_eq!(name, "Op");
+                assert_eq!(paths, vec![PathBuf::from("a.graphql.ts"), PathBuf::from("b.graphql.ts")]);
+            }
+            other => panic!("expected DuplicateOperation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn diff_signatures_reports_clean_when_matching() {
+        let recorded: BTreeMap<String, String> = BTreeMap::from([("Op".to_string(), "hash".to_string())]);
+        let current = recorded.clone();
+        assert!(diff_signatures(&recorded, &current).is_clean());
+    }
+
+    #[test]
+    fn diff_signatures_reports_stale_hash() {
+        let recorded: BTreeMap<String, String> = BTreeMap::from([("Op".to_string(), "old-hash".to_string())]);
+        let current: BTreeMap<String, String> = BTreeMap::from([("Op".to_string(), "new-hash".to_string())]);
+        let report = diff_signatures(&recorded, &current);
+        assert_eq!(report.stale, vec!["Op".to_string()]);
+        assert!(report.missing_from_tree.is_empty());
+        assert!(report.missing_from_file.is_empty());
+    }
+
+    #[test]
+    fn diff_signatures_reports_missing_from_tree() {
+        let recorded: BTreeMap<String, String> = BTreeMap::from([("Op".to_string(), "hash".to_string())]);
+        let current = BTreeMap::new();
+        let report = diff_signatures(&recorded, &current);
+        assert_eq!(report.missing_from_tree, vec!["Op".to_string()]);
+        assert!(report.stale.is_empty());
+        assert!(report.missing_from_file.is_empty());
+    }
+
+    #[test]
+    fn diff_signatures_reports_missing_from_file() {
+        let recorded = BTreeMap::new();
+        let current: BTreeMap<String, String> = BTreeMap::from([("Op".to_string(), "hash".to_string())]);
+        let report = diff_signatures(&recorded, &current);
+        assert_eq!(report.missing_from_file, vec!["Op".to_string()]);
+        assert!(report.stale.is_empty());
+        assert!(report.missing_from_tree.is_empty());
+    }
+}